@@ -1,8 +1,15 @@
+use std::collections::HashMap;
+use std::fs::File as StdFile;
+use std::io::BufReader;
+use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 use std::time::SystemTime;
 
+use aws_config::sts::AssumeRoleProvider;
 use aws_config::BehaviorVersion;
 use aws_credential_types::provider::ProvideCredentials;
+use aws_credential_types::provider::SharedCredentialsProvider;
 use aws_sigv4::http_request::sign;
 use aws_sigv4::http_request::SignableBody;
 use aws_sigv4::http_request::SignableRequest;
@@ -11,19 +18,35 @@ use aws_sigv4::sign::v4;
 use bytes::{Bytes, BytesMut};
 use eyre::{eyre, Result};
 use futures::SinkExt;
-use postgres_native_tls::TlsConnector;
-use postgres_native_tls::TlsStream;
 use postgres_protocol::message::backend::Message;
 use postgres_protocol::message::frontend;
+use rustls::pki_types::ServerName;
 use tokio::io::AsyncRead;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWrite;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
-use tokio_postgres::tls::TlsConnect;
+use tokio::sync::Mutex;
+use tokio::sync::RwLock;
+use tokio_rustls::rustls;
+use tokio_rustls::TlsConnector;
+use tokio_socks::tcp::Socks5Stream;
 use tokio_stream::StreamExt;
 use tokio_util::codec::BytesCodec;
 use tokio_util::codec::Framed;
+use tracing::debug;
+
+/// Session name used when assuming a role without an explicit
+/// `session_name` configured.
+const DEFAULT_ASSUME_ROLE_SESSION_NAME: &str = "rds-iamauth-proxy";
+
+/// The backend leg's TLS stream type, keeping the naming symmetric with the
+/// client-facing [`tokio_rustls::server::TlsStream`] used for termination.
+pub type BackendTlsStream<S> = tokio_rustls::client::TlsStream<S>;
+
+/// Protocol the client negotiates over the client-facing TLS connection; RDS
+/// and modern Postgres clients advertise this for PostgreSQL 17 direct SSL.
+pub(crate) const POSTGRES_ALPN_PROTOCOL: &[u8] = b"postgresql";
 
 #[derive(Debug)]
 pub struct DbSpec {
@@ -36,6 +59,14 @@ impl DbSpec {
         DbSpec { user, database }
     }
 
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn database(&self) -> &str {
+        &self.database
+    }
+
     fn startup_message(&self) -> Result<Bytes> {
         let mut params = vec![("client_encoding", "UTF8")];
         params.push(("user", self.user.as_str()));
@@ -58,29 +89,247 @@ impl Addr {
     }
 }
 
+/// How to dial the backend leg: direct, or through a relay/bastion.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ProxyKind {
+    Tcp,
+    Socks5,
+}
+
+/// A relay this backend dials through to reach `endpoint`, for deployments
+/// where RDS is only reachable via a bastion/SOCKS tunnel. TLS and hostname
+/// verification still target `endpoint`, not `addr`, regardless of kind.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BackendProxyConfig {
+    kind: ProxyKind,
+    addr: Addr,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+/// How a backend obtains the AWS credentials used to sign its IAM auth
+/// tokens, letting one proxy instance front RDS clusters in different
+/// accounts under the correct identity.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CredentialsSource {
+    /// Use a named profile from the shared AWS config/credentials files.
+    Profile { name: String },
+    /// Assume a role, using the default credential provider chain as the
+    /// base credentials.
+    AssumeRole {
+        role_arn: String,
+        external_id: Option<String>,
+        session_name: Option<String>,
+    },
+}
+
+/// Identifies an IAM auth token: it's only valid for a given user connecting
+/// to a given RDS endpoint.
+type TokenKey = (String, String, u16);
+
+#[derive(Clone, Debug)]
+struct CachedToken {
+    password: String,
+    issued_at: Instant,
+}
+
+fn default_token_soft_ttl_secs() -> u64 {
+    600
+}
+
+/// Caches signed IAM auth tokens so a burst of new connections for the same
+/// (user, host, port) triggers a single SigV4 signing call instead of one
+/// per connection. Tokens are valid for RDS's full 900s `expires_in` window;
+/// entries are re-signed once older than `BackendConfig::token_soft_ttl`.
+///
+/// Misses are collapsed per key via a `Mutex` held across the signing await:
+/// the first caller for a key signs while later callers for the same key
+/// block on the same lock, then find a fresh entry already waiting for them.
+#[derive(Clone, Debug, Default)]
+struct TokenCache {
+    entries: Arc<RwLock<HashMap<TokenKey, Arc<Mutex<Option<CachedToken>>>>>>,
+}
+
+impl TokenCache {
+    async fn slot_for(&self, key: TokenKey) -> Arc<Mutex<Option<CachedToken>>> {
+        if let Some(slot) = self.entries.read().await.get(&key) {
+            return slot.clone();
+        }
+        self.entries
+            .write()
+            .await
+            .entry(key)
+            .or_insert_with(|| Arc::new(Mutex::new(None)))
+            .clone()
+    }
+
+    async fn get_or_sign(&self, config: &BackendConfig, key: TokenKey) -> Result<String> {
+        let slot = self.slot_for(key.clone()).await;
+        let mut cached = slot.lock().await;
+        if let Some(token) = cached.as_ref() {
+            if token.issued_at.elapsed() < config.token_soft_ttl() {
+                return Ok(token.password.clone());
+            }
+        }
+        let provider = config
+            .credentials_provider
+            .as_ref()
+            .ok_or_else(|| eyre!("backend not initialized: missing credentials provider"))?;
+        let (user, host, port) = &key;
+        let password =
+            get_rds_password(host, *port, config.region.as_str(), user, provider).await?;
+        *cached = Some(CachedToken {
+            password: password.clone(),
+            issued_at: Instant::now(),
+        });
+        Ok(password)
+    }
+}
+
+/// Client-facing TLS termination: the cert/key the proxy presents to
+/// connecting clients. The backend leg's trusted roots are configured
+/// separately on [`BackendConfig`] (`tls_ca_bundle`, `trust_os_roots`,
+/// `trust_webpki_roots`).
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClientTlsConfig {
+    cert_path: String,
+    key_path: String,
+}
+
+impl ClientTlsConfig {
+    pub fn server_config(&self) -> Result<Arc<rustls::ServerConfig>> {
+        let cert_file = StdFile::open(&self.cert_path)?;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let key_file = StdFile::open(&self.key_path)?;
+        let key = rustls_pemfile::private_key(&mut BufReader::new(key_file))?
+            .ok_or_else(|| eyre!("no private key found in {}", self.key_path))?;
+
+        let mut server_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        server_config.alpn_protocols = vec![POSTGRES_ALPN_PROTOCOL.to_vec()];
+        Ok(Arc::new(server_config))
+    }
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct BackendConfig {
     endpoint: Addr,
     region: String,
-    proxy_endpoint: Option<Addr>,
+    proxy: Option<BackendProxyConfig>,
+    /// PEM bundle of trusted CAs for the backend leg, e.g. the Amazon RDS
+    /// global CA bundle. Entries that fail to parse are skipped rather than
+    /// failing the backend connection.
+    tls_ca_bundle: Option<String>,
+    /// Opt-in fallback to trust the OS's native certificate store.
+    #[serde(default)]
+    trust_os_roots: bool,
+    /// Opt-in fallback to trust the Mozilla root set bundled via webpki-roots.
+    #[serde(default)]
+    trust_webpki_roots: bool,
+    /// Soft TTL, in seconds, before a cached IAM auth token is re-signed.
+    /// RDS tokens stay valid for 900s; the default refreshes well ahead of
+    /// that so a connection never races an expiring token.
+    #[serde(default = "default_token_soft_ttl_secs")]
+    token_soft_ttl_secs: u64,
+    /// How to obtain AWS credentials for this backend. Defaults to the
+    /// ambient credential provider chain when absent.
+    credentials: Option<CredentialsSource>,
+    /// Resolved once at startup by [`BackendConfig::initialize`]; absent
+    /// (and unusable) until then.
+    #[serde(skip)]
+    credentials_provider: Option<SharedCredentialsProvider>,
+    #[serde(skip)]
+    token_cache: TokenCache,
 }
 
 impl BackendConfig {
-    fn connect_endpoint(&self) -> &Addr {
-        match self.proxy_endpoint {
-            Some(ref proxy) => proxy,
-            None => &self.endpoint,
+    /// Loads the AWS config and resolves this backend's credentials provider
+    /// once; called a single time at startup rather than per connection like
+    /// `get_rds_password` used to. Must run before the first
+    /// `get_server_conn`.
+    pub async fn initialize(&mut self) -> Result<()> {
+        let mut loader = aws_config::defaults(BehaviorVersion::v2023_11_09())
+            .region(aws_types::region::Region::new(self.region.clone()));
+        if let Some(CredentialsSource::Profile { name }) = &self.credentials {
+            loader = loader.profile_name(name.clone());
+        }
+        let sdk_config = loader.load().await;
+
+        let provider = match &self.credentials {
+            Some(CredentialsSource::AssumeRole {
+                role_arn,
+                external_id,
+                session_name,
+            }) => {
+                let mut builder = AssumeRoleProvider::builder(role_arn.clone())
+                    .session_name(
+                        session_name
+                            .clone()
+                            .unwrap_or_else(|| DEFAULT_ASSUME_ROLE_SESSION_NAME.to_owned()),
+                    )
+                    .configure(&sdk_config);
+                if let Some(external_id) = external_id {
+                    builder = builder.external_id(external_id.clone());
+                }
+                SharedCredentialsProvider::new(builder.build().await)
+            }
+            _ => sdk_config
+                .credentials_provider()
+                .ok_or_else(|| eyre!("no credentials provider found"))?,
+        };
+
+        self.credentials_provider = Some(provider);
+        Ok(())
+    }
+
+    fn token_soft_ttl(&self) -> Duration {
+        Duration::from_secs(self.token_soft_ttl_secs)
+    }
+
+    /// Opens the raw TCP byte stream to `endpoint`, direct or through the
+    /// configured relay. TLS is layered on top of this by `upgrade_to_tls`,
+    /// which always verifies against `endpoint`'s hostname regardless of how
+    /// the bytes got there.
+    async fn dial_backend(&self) -> Result<TcpStream> {
+        match &self.proxy {
+            None => Ok(TcpStream::connect(self.endpoint.connect_str()).await?),
+            Some(proxy) => match proxy.kind {
+                ProxyKind::Tcp => Ok(TcpStream::connect(proxy.addr.connect_str()).await?),
+                ProxyKind::Socks5 => {
+                    let target = self.endpoint.connect_str();
+                    let socks_stream = match (&proxy.username, &proxy.password) {
+                        (Some(username), Some(password)) => {
+                            Socks5Stream::connect_with_password(
+                                proxy.addr.connect_str().as_str(),
+                                target.as_str(),
+                                username.as_str(),
+                                password.as_str(),
+                            )
+                            .await?
+                        }
+                        _ => {
+                            Socks5Stream::connect(proxy.addr.connect_str().as_str(), target.as_str())
+                                .await?
+                        }
+                    };
+                    Ok(socks_stream.into_inner())
+                }
+            },
         }
     }
 
-    pub async fn get_server_conn(&self, db_spec: DbSpec) -> Result<TlsStream<TcpStream>> {
-        let password = get_rds_password(
-            self.endpoint.hostname.as_ref(),
+    pub async fn get_server_conn(&self, db_spec: DbSpec) -> Result<BackendTlsStream<TcpStream>> {
+        let key = (
+            db_spec.user.clone(),
+            self.endpoint.hostname.clone(),
             self.endpoint.port,
-            self.region.as_ref(),
-            db_spec.user.as_str(),
-        )
-        .await?;
+        );
+        let password = self.token_cache.get_or_sign(self, key).await?;
         let stream = self.backend_conn(db_spec, password).await?;
         Ok(stream)
     }
@@ -89,14 +338,51 @@ impl BackendConfig {
         &self,
         db_spec: DbSpec,
         password: String,
-    ) -> Result<TlsStream<TcpStream>> {
-        let stream = TcpStream::connect(self.connect_endpoint().connect_str()).await?;
+    ) -> Result<BackendTlsStream<TcpStream>> {
+        let stream = self.dial_backend().await?;
         let mut tls_stream = self.upgrade_to_tls(stream).await?;
         send_password(&db_spec, &mut tls_stream, password).await?;
         Ok(tls_stream)
     }
 
-    async fn upgrade_to_tls<S>(&self, mut tcp: S) -> Result<TlsStream<S>>
+    /// Assembles the root store trusted for the backend leg: the configured
+    /// RDS CA bundle plus whichever opt-in fallbacks are enabled. Built anew
+    /// for each backend connection. A cert that fails to parse is skipped
+    /// with a debug log instead of failing the connection, so one bad entry
+    /// in a large bundle doesn't break the proxy.
+    fn backend_root_store(&self) -> Result<rustls::RootCertStore> {
+        let mut root_store = rustls::RootCertStore::empty();
+
+        if let Some(bundle_path) = &self.tls_ca_bundle {
+            let bundle_file = StdFile::open(bundle_path)?;
+            for cert in rustls_pemfile::certs(&mut BufReader::new(bundle_file)) {
+                match cert {
+                    Ok(cert) => {
+                        if let Err(e) = root_store.add(cert) {
+                            debug!("skipping unparseable entry in {}: {}", bundle_path, e);
+                        }
+                    }
+                    Err(e) => debug!("skipping unreadable entry in {}: {}", bundle_path, e),
+                }
+            }
+        }
+
+        if self.trust_os_roots {
+            for cert in rustls_native_certs::load_native_certs().certs {
+                if let Err(e) = root_store.add(cert) {
+                    debug!("skipping unparseable OS root certificate: {}", e);
+                }
+            }
+        }
+
+        if self.trust_webpki_roots {
+            root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+
+        Ok(root_store)
+    }
+
+    async fn upgrade_to_tls<S>(&self, mut tcp: S) -> Result<BackendTlsStream<S>>
     where
         S: AsyncRead + AsyncWrite + Unpin + 'static + Send,
     {
@@ -106,15 +392,19 @@ impl BackendConfig {
         let mut buf = [0];
         tcp.read_exact(&mut buf).await?;
         if buf[0] != b'S' {
-            Err(eyre!("server does not support TLS"))
-        } else {
-            let native_conn = native_tls::TlsConnector::builder()
-                .danger_accept_invalid_certs(true)
-                .build()?;
-            let tls = TlsConnector::new(native_conn, self.endpoint.hostname.as_ref());
-            let stream = tls.connect(tcp).await?;
-            Ok(stream)
+            return Err(eyre!("server does not support TLS"));
         }
+        let client_config = rustls::ClientConfig::builder()
+            .with_root_certificates(self.backend_root_store()?)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+        // Always verify against the real RDS endpoint hostname, even when
+        // dialing through a relay or SOCKS tunnel, since that's the identity
+        // the certificate actually attests to.
+        let domain = ServerName::try_from(self.endpoint.hostname.clone())
+            .map_err(|_| eyre!("invalid backend hostname {}", self.endpoint.hostname))?;
+        let stream = connector.connect(domain, tcp).await?;
+        Ok(stream)
     }
 }
 
@@ -125,12 +415,9 @@ pub async fn get_rds_password(
     port: u16,
     region_name: &str,
     username: &str,
+    credentials_provider: &SharedCredentialsProvider,
 ) -> Result<String> {
-    let config = aws_config::load_defaults(BehaviorVersion::v2023_11_09()).await;
-    let provider = config
-        .credentials_provider()
-        .ok_or(eyre!("no credentials provider found"))?;
-    let creds = provider.provide_credentials().await?;
+    let creds = credentials_provider.provide_credentials().await?;
     let identity = creds.into();
 
     let mut signing_settings = SigningSettings::default();
@@ -4,25 +4,43 @@ extern crate serde_derive;
 use crate::backend_config::DbSpec;
 use byteorder::BigEndian;
 use byteorder::ByteOrder;
+use bytes::BufMut;
 use bytes::Bytes;
+use bytes::BytesMut;
 use config::Config;
 use config::File;
 use eyre::{eyre, Result};
 use futures::SinkExt;
 use memchr::memchr;
-use postgres_native_tls::TlsStream;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::io::split;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
 use tokio_stream::StreamExt;
 use tokio_util::codec::{BytesCodec, Decoder};
 use tracing::{debug, info};
 use tracing_subscriber::EnvFilter;
 
 mod backend_config;
-use backend_config::BackendConfig;
+use backend_config::BackendTlsStream;
+
+mod router;
+use router::ProxyConfig;
+use router::Router;
+
+/// Marker trait for the client-facing byte stream, which is either a plain
+/// `TcpStream` or a `tokio_rustls` stream once client TLS termination is
+/// enabled. Letting `handle_client` operate on a boxed trait object keeps the
+/// rest of the pipeline (startup parsing, proxying) oblivious to which one it
+/// got.
+trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientStream for T {}
 
 fn setup() -> Result<()> {
     if std::env::var("RUST_LIB_BACKTRACE").is_err() {
@@ -42,6 +60,31 @@ fn setup() -> Result<()> {
 const SSL_REQUEST: i32 = 80877103;
 const STARTUP_MESSAGE: i32 = 196608;
 const SSL_NOT_ALLOWED: u8 = 0x4e;
+/// First byte of a TLS handshake record, used to recognize PostgreSQL 17
+/// "direct SSL" clients that skip the classic `SSLRequest` negotiation and
+/// start the TLS handshake as soon as the TCP connection is accepted.
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+/// Builds a Postgres `ErrorResponse` message so a routing failure reaches
+/// the client as a normal error instead of a connection that just vanishes.
+fn error_response(code: &str, message: &str) -> Bytes {
+    let mut buf = BytesMut::new();
+    buf.put_u8(b'E');
+    let len_pos = buf.len();
+    buf.put_i32(0);
+    buf.put_u8(b'S');
+    buf.put_slice(b"FATAL\0");
+    buf.put_u8(b'C');
+    buf.put_slice(code.as_bytes());
+    buf.put_u8(0);
+    buf.put_u8(b'M');
+    buf.put_slice(message.as_bytes());
+    buf.put_u8(0);
+    buf.put_u8(0);
+    let len = (buf.len() - len_pos) as i32;
+    buf[len_pos..len_pos + 4].copy_from_slice(&len.to_be_bytes());
+    buf.freeze()
+}
 
 struct Buffer {
     bytes: Bytes,
@@ -94,10 +137,58 @@ fn parse_startup(src: Bytes) -> Result<DbSpec> {
     Ok(db)
 }
 
-async fn auth_backend(
-    config: &BackendConfig,
-    client: &mut TcpStream,
-) -> Result<TlsStream<TcpStream>> {
+/// Performs the client-facing TLS handshake, supporting both the classic
+/// `SSLRequest` negotiation and PostgreSQL 17's "direct SSL" mode, and
+/// returns the decrypted stream. Everything after this point (startup
+/// parsing, backend auth) is unaware that TLS ever happened.
+async fn negotiate_client_tls(
+    acceptor: &TlsAcceptor,
+    mut client: TcpStream,
+) -> Result<tokio_rustls::server::TlsStream<TcpStream>> {
+    let mut peek_buf = [0u8; 1];
+    let n = client.peek(&mut peek_buf).await?;
+    if n == 1 && peek_buf[0] == TLS_HANDSHAKE_RECORD {
+        debug!("client opened a direct SSL connection");
+        let tls = acceptor.accept(client).await?;
+        if tls.get_ref().1.alpn_protocol() != Some(backend_config::POSTGRES_ALPN_PROTOCOL) {
+            return Err(eyre!(
+                "direct SSL client did not negotiate the postgresql ALPN protocol"
+            ));
+        }
+        Ok(tls)
+    } else {
+        let mut header = [0u8; 8];
+        client.read_exact(&mut header).await?;
+        let len = BigEndian::read_i32(&header[0..]);
+        let tag = BigEndian::read_i32(&header[4..]);
+        if len != 8 || tag != SSL_REQUEST {
+            return Err(eyre!(
+                "expected an SSLRequest from a TLS-only proxy, got len {} tag {}",
+                len,
+                tag
+            ));
+        }
+        client.write_all(&[b'S']).await?;
+        Ok(acceptor.accept(client).await?)
+    }
+}
+
+/// Produces the client-facing byte stream, terminating TLS on it first when
+/// the proxy is configured with a client cert/key pair.
+async fn establish_client_stream(
+    tls_acceptor: Option<&TlsAcceptor>,
+    client: TcpStream,
+) -> Result<Box<dyn ClientStream>> {
+    match tls_acceptor {
+        Some(acceptor) => Ok(Box::new(negotiate_client_tls(acceptor, client).await?)),
+        None => Ok(Box::new(client)),
+    }
+}
+
+async fn read_startup<S>(router: &Router, client: &mut S) -> Result<BackendTlsStream<TcpStream>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
     let mut framed = BytesCodec::new().framed(client);
     while let Some(message) = framed.next().await {
         match message {
@@ -118,7 +209,16 @@ async fn auth_backend(
                             ));
                         }
                         let db = parse_startup(bytes.split_off(8).freeze())?;
-                        let server = config.get_server_conn(db).await?;
+                        let (backend, db) = match router.route(db) {
+                            Ok(routed) => routed,
+                            Err(e) => {
+                                framed
+                                    .send(error_response(e.sqlstate(), &e.to_string()))
+                                    .await?;
+                                return Err(e.into());
+                            }
+                        };
+                        let server = backend.get_server_conn(db).await?;
                         return Ok(server);
                     } else {
                         return Err(eyre!("Unknown message tag {}", tag));
@@ -134,13 +234,15 @@ async fn auth_backend(
 }
 
 async fn handle_client(
-    config: &BackendConfig,
-    mut client: TcpStream,
+    router: &Router,
+    tls_acceptor: Option<&TlsAcceptor>,
+    client: TcpStream,
     _addr: SocketAddr,
 ) -> Result<()> {
-    let server = auth_backend(config, &mut client).await?;
+    let mut client = establish_client_stream(tls_acceptor, client).await?;
+    let server = read_startup(router, client.as_mut()).await?;
 
-    let (mut ri, mut wi) = client.split();
+    let (mut ri, mut wi) = split(client);
     let (mut ro, mut wo) = split(server);
     let client_to_server = async {
         tokio::io::copy(&mut ri, &mut wo).await?;
@@ -155,16 +257,22 @@ async fn handle_client(
     Ok(())
 }
 
-async fn run_proxy(config: BackendConfig) -> Result<()> {
+async fn run_proxy(router: Router) -> Result<()> {
     let listener = TcpListener::bind("127.0.0.1:5435").await?;
     info!("Listening");
+    let tls_acceptor = router
+        .client_tls()
+        .map(|tls| tls.server_config().map(TlsAcceptor::from))
+        .transpose()?;
+    let router = Arc::new(router);
     loop {
         let (stream, addr) = listener.accept().await?;
 
         info!("Got connection");
-        let config_copy = config.clone();
+        let router = router.clone();
+        let tls_acceptor = tls_acceptor.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_client(&config_copy, stream, addr).await {
+            if let Err(e) = handle_client(&router, tls_acceptor.as_ref(), stream, addr).await {
                 info!("An error occurred in a client {:?}", e);
             } else {
                 info!("done with client");
@@ -173,7 +281,7 @@ async fn run_proxy(config: BackendConfig) -> Result<()> {
     }
 }
 
-fn load_config() -> Result<BackendConfig> {
+fn load_config() -> Result<ProxyConfig> {
     let mut s = Config::default();
     s.merge(File::with_name("proxy"))?;
     s.try_into().map_err(|e| e.into())
@@ -182,7 +290,8 @@ fn load_config() -> Result<BackendConfig> {
 #[tokio::main]
 async fn main() -> Result<()> {
     setup()?;
-    let backend_config = load_config()?;
-    run_proxy(backend_config).await?;
+    let mut router = Router::new(load_config()?)?;
+    router.initialize().await?;
+    run_proxy(router).await?;
     Ok(())
 }
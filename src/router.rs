@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use eyre::{eyre, Result};
+
+use crate::backend_config::BackendConfig;
+use crate::backend_config::ClientTlsConfig;
+use crate::backend_config::DbSpec;
+
+/// One entry of the `backends` list in `proxy.toml`: a `BackendConfig` with
+/// the name routes refer to it by.
+#[derive(Clone, Debug, Deserialize)]
+pub struct NamedBackend {
+    name: String,
+    #[serde(flatten)]
+    config: BackendConfig,
+}
+
+/// Maps a client's requested database (and optionally user) to a backend.
+/// The first matching route wins; `rewrite_database` lets the client keep
+/// using a friendly alias while the backend sees the real database name.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RouteConfig {
+    database: String,
+    user: Option<String>,
+    backend: String,
+    rewrite_database: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProxyConfig {
+    /// Client-facing TLS termination, shared by every backend since it
+    /// happens before a route is even chosen.
+    tls: Option<ClientTlsConfig>,
+    backends: Vec<NamedBackend>,
+    #[serde(default)]
+    routes: Vec<RouteConfig>,
+    default_backend: Option<String>,
+}
+
+/// No configured route matched the client's requested database/user, and no
+/// `default_backend` was configured to fall back to.
+#[derive(Debug)]
+pub struct NoRouteError {
+    pub database: String,
+    pub user: String,
+    /// True when a route for `database` exists but restricts to a different
+    /// `user`, i.e. this is an authorization mismatch rather than an
+    /// unknown database.
+    pub user_mismatch: bool,
+}
+
+impl NoRouteError {
+    /// SQLSTATE to report to the client: `invalid_authorization_specification`
+    /// when the database is known but this user isn't routed to it,
+    /// `invalid_catalog_name` when the database has no route at all.
+    pub fn sqlstate(&self) -> &'static str {
+        if self.user_mismatch {
+            "28000"
+        } else {
+            "3D000"
+        }
+    }
+}
+
+impl fmt::Display for NoRouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no route for database \"{}\" user \"{}\"",
+            self.database, self.user
+        )
+    }
+}
+
+impl std::error::Error for NoRouteError {}
+
+/// Resolves an incoming client connection's requested database/user to a
+/// backend, built once from [`ProxyConfig`] at startup.
+pub struct Router {
+    tls: Option<ClientTlsConfig>,
+    backends: HashMap<String, BackendConfig>,
+    routes: Vec<RouteConfig>,
+    default_backend: Option<String>,
+}
+
+impl Router {
+    pub fn new(config: ProxyConfig) -> Result<Router> {
+        let mut backends = HashMap::with_capacity(config.backends.len());
+        for named in config.backends {
+            if backends.insert(named.name.clone(), named.config).is_some() {
+                return Err(eyre!("duplicate backend name \"{}\"", named.name));
+            }
+        }
+        for route in &config.routes {
+            if !backends.contains_key(&route.backend) {
+                return Err(eyre!(
+                    "route for database \"{}\" references unknown backend \"{}\"",
+                    route.database,
+                    route.backend
+                ));
+            }
+        }
+        if let Some(default) = &config.default_backend {
+            if !backends.contains_key(default) {
+                return Err(eyre!(
+                    "default_backend \"{}\" is not a known backend",
+                    default
+                ));
+            }
+        }
+        Ok(Router {
+            tls: config.tls,
+            backends,
+            routes: config.routes,
+            default_backend: config.default_backend,
+        })
+    }
+
+    /// Resolves credentials for every backend. Each backend's
+    /// initialization is independent (its own profile/assume-role chain,
+    /// its own AWS config), so they run concurrently rather than one at a
+    /// time.
+    pub async fn initialize(&mut self) -> Result<()> {
+        let inits = self.backends.values_mut().map(|backend| backend.initialize());
+        futures::future::try_join_all(inits).await?;
+        Ok(())
+    }
+
+    pub fn client_tls(&self) -> Option<&ClientTlsConfig> {
+        self.tls.as_ref()
+    }
+
+    /// Picks the backend for a parsed startup packet: the first route whose
+    /// `database` matches (and whose `user`, if set, also matches), else
+    /// `default_backend`. Returns the `DbSpec` to send downstream, rewritten
+    /// to the route's real database name when it has one.
+    pub fn route(&self, db_spec: DbSpec) -> Result<(&BackendConfig, DbSpec), NoRouteError> {
+        let matched = self.routes.iter().find(|route| {
+            route.database == db_spec.database()
+                && route
+                    .user
+                    .as_deref()
+                    .map_or(true, |user| user == db_spec.user())
+        });
+
+        let backend_name = matched
+            .map(|route| route.backend.as_str())
+            .or(self.default_backend.as_deref())
+            .ok_or_else(|| NoRouteError {
+                database: db_spec.database().to_owned(),
+                user: db_spec.user().to_owned(),
+                user_mismatch: self
+                    .routes
+                    .iter()
+                    .any(|route| route.database == db_spec.database()),
+            })?;
+
+        let backend = self
+            .backends
+            .get(backend_name)
+            .expect("route and default_backend names are validated in Router::new");
+
+        let spec = match matched.and_then(|route| route.rewrite_database.clone()) {
+            Some(database) => DbSpec::new(db_spec.user().to_owned(), database),
+            None => db_spec,
+        };
+
+        Ok((backend, spec))
+    }
+}